@@ -5,13 +5,25 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+mod dedup;
+mod vfs;
+
 use crate::errors::BuildProjectError;
 use log::info;
-use serde::{Serialize, Serializer};
-use std::fs::{create_dir_all, File};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
 use std::io;
 use std::io::prelude::*;
-use std::{path::PathBuf, sync::Mutex};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+pub use dedup::ArtifactDedupWriter;
+pub use vfs::{ArtifactFileSystem, InMemoryArtifactFileSystem, OsArtifactFileSystem};
 
 type BuildProjectResult = Result<(), BuildProjectError>;
 
@@ -19,34 +31,212 @@ pub trait ArtifactWriter {
     fn write_if_changed(&self, path: PathBuf, content: Vec<u8>) -> BuildProjectResult;
     fn remove(&self, path: PathBuf) -> BuildProjectResult;
     fn finalize(&self) -> crate::errors::Result<()>;
+
+    /// The version of the running compiler. Writers that persist a sidecar
+    /// index ([`ArtifactFileWriter`]'s hash index, [`ArtifactDedupWriter`]'s
+    /// refcount state) record this alongside each artifact path, so that
+    /// artifacts produced by a different compiler version are detected as
+    /// stale and unconditionally rewritten — without stamping anything into
+    /// the artifact's own bytes. Unlike rustc's incremental cache, these are
+    /// the project's real generated source files, which consumers
+    /// `import`/`require` directly, so nothing can be prepended to their
+    /// content.
+    fn compiler_version(&self) -> &str;
 }
 
-pub struct ArtifactFileWriter;
+/// Writes artifacts to disk, consulting a persisted content-hash index
+/// first: when the index says an artifact is unchanged, both the
+/// read-and-byte-compare of the existing file and the write are skipped.
+pub struct ArtifactFileWriter {
+    fs: Arc<dyn ArtifactFileSystem>,
+    hash_index: Mutex<ArtifactHashIndex>,
+    hash_index_path: PathBuf,
+    compiler_version: String,
+}
+
+impl ArtifactFileWriter {
+    pub fn new(hash_index_path: PathBuf, compiler_version: String) -> Self {
+        Self::with_fs(
+            hash_index_path,
+            compiler_version,
+            Arc::new(OsArtifactFileSystem),
+        )
+    }
+
+    /// Like [`ArtifactFileWriter::new`], but routes all IO through `fs`
+    /// instead of `std::fs` — e.g. an [`InMemoryArtifactFileSystem`] for a
+    /// hermetic test of the build pipeline.
+    pub fn with_fs(
+        hash_index_path: PathBuf,
+        compiler_version: String,
+        fs: Arc<dyn ArtifactFileSystem>,
+    ) -> Self {
+        ArtifactFileWriter {
+            hash_index: Mutex::new(ArtifactHashIndex::load(fs.as_ref(), &hash_index_path)),
+            hash_index_path,
+            compiler_version,
+            fs,
+        }
+    }
+}
 
 impl ArtifactWriter for ArtifactFileWriter {
     fn write_if_changed(&self, path: PathBuf, content: Vec<u8>) -> BuildProjectResult {
-        write_file(&path, &content).map_err(|error| BuildProjectError::WriteFileError {
-            file: path,
-            source: error,
-        })
+        let content_hash = hash_content(&content);
+        let content_len = content.len() as u64;
+        let (is_up_to_date, version_is_stale) = {
+            let hash_index = self.hash_index.lock().unwrap();
+            let version_is_stale = hash_index.entries.get(&path).map_or(false, |entry| {
+                entry.compiler_version != self.compiler_version
+            });
+            let is_up_to_date = hash_index.is_up_to_date(
+                self.fs.as_ref(),
+                &path,
+                content_hash,
+                content_len,
+                &self.compiler_version,
+            );
+            (is_up_to_date, version_is_stale)
+        };
+        if is_up_to_date {
+            return Ok(());
+        }
+
+        let write_result = if version_is_stale {
+            ensure_file_directory_exists(self.fs.as_ref(), &path)
+                .and_then(|_| self.fs.write(&path, &content))
+        } else {
+            write_file(self.fs.as_ref(), &path, &content)
+        };
+        write_result.map_err(|error| {
+            BuildProjectError::WriteFileError {
+                file: path.clone(),
+                source: error,
+            }
+        })?;
+
+        self.hash_index.lock().unwrap().record(
+            self.fs.as_ref(),
+            path,
+            content_hash,
+            content_len,
+            self.compiler_version.clone(),
+        );
+        Ok(())
     }
 
     fn remove(&self, path: PathBuf) -> BuildProjectResult {
-        std::fs::remove_file(&path).unwrap_or_else(|_| {
+        self.fs.remove(&path).unwrap_or_else(|_| {
             info!("tried to delete already deleted file: {:?}", path);
         });
+        self.hash_index.lock().unwrap().entries.remove(&path);
         Ok(())
     }
 
     fn finalize(&self) -> crate::errors::Result<()> {
-        Ok(())
+        self.hash_index
+            .lock()
+            .unwrap()
+            .save(self.fs.as_ref(), &self.hash_index_path)
+            .map_err(|error| crate::errors::Error::WriteFileError {
+                file: self.hash_index_path.clone(),
+                source: error,
+            })
+    }
+
+    fn compiler_version(&self) -> &str {
+        &self.compiler_version
     }
 }
 
+/// A persisted `path -> last-written-content` fingerprint, used to skip
+/// reading an artifact off disk just to byte-compare it. An entry is only
+/// trusted when the hash, size, on-disk mtime, and compiler version all
+/// still match what was recorded. An entry recorded by a different compiler
+/// version makes the artifact be rewritten unconditionally; any other
+/// mismatch (including no entry at all) falls back to the full
+/// read-and-compare in [`write_file`]. Tracking
+/// `compiler_version` here (rather than stamping it into the artifact's own
+/// bytes) is what makes a compiler upgrade force every artifact to be
+/// treated as stale.
+#[derive(Default, Serialize, Deserialize)]
+struct ArtifactHashIndex {
+    entries: HashMap<PathBuf, ArtifactHashEntry>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct ArtifactHashEntry {
+    hash: u64,
+    size: u64,
+    mtime_nanos: u128,
+    compiler_version: String,
+}
+
+impl ArtifactHashIndex {
+    fn load(fs: &dyn ArtifactFileSystem, path: &Path) -> ArtifactHashIndex {
+        fs.read(path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, fs: &dyn ArtifactFileSystem, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_vec(self)
+            .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+        fs.write(path, &json)
+    }
+
+    fn is_up_to_date(
+        &self,
+        fs: &dyn ArtifactFileSystem,
+        path: &Path,
+        content_hash: u64,
+        content_len: u64,
+        compiler_version: &str,
+    ) -> bool {
+        let entry = match self.entries.get(path) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        entry.hash == content_hash
+            && entry.size == content_len
+            && entry.compiler_version == compiler_version
+            && fs.modified_since_epoch_nanos(path) == Some(entry.mtime_nanos)
+    }
+
+    fn record(
+        &mut self,
+        fs: &dyn ArtifactFileSystem,
+        path: PathBuf,
+        content_hash: u64,
+        content_len: u64,
+        compiler_version: String,
+    ) {
+        if let Some(mtime_nanos) = fs.modified_since_epoch_nanos(&path) {
+            self.entries.insert(
+                path,
+                ArtifactHashEntry {
+                    hash: content_hash,
+                    size: content_len,
+                    mtime_nanos,
+                    compiler_version,
+                },
+            );
+        }
+    }
+}
+
+fn hash_content(content: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
 #[derive(Serialize)]
 struct CodegenRecords {
     pub removed: Vec<ArtifactDeletionRecord>,
     pub changed: Vec<ArtifactUpdateRecord>,
+    pub patched: Vec<ArtifactPatchRecord>,
 }
 
 #[derive(Serialize)]
@@ -61,54 +251,98 @@ struct ArtifactUpdateRecord {
     pub data: Vec<u8>,
 }
 
+/// Recorded in place of an [`ArtifactUpdateRecord`] when the artifact
+/// already exists on disk and neither version has more than
+/// `MAX_DIFFABLE_LINES` lines: instead of the whole new content, `unified_diff`
+/// holds just the changed lines (in `diff -u` hunk format), so a large
+/// generated file that changes by a few lines doesn't bloat the
+/// codegen-records JSON with an unchanged copy of the rest of the file.
+#[derive(Serialize)]
+struct ArtifactPatchRecord {
+    pub path: PathBuf,
+    pub unified_diff: String,
+}
+
 fn from_utf8<S>(slice: &Vec<u8>, s: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
-    s.serialize_str(std::str::from_utf8(slice).unwrap())
+    // Artifact content should always be UTF-8, but a corrupt or
+    // unexpectedly-binary artifact shouldn't take down codegen-record
+    // serialization; fall back to a lossy conversion instead of unwrapping.
+    s.serialize_str(&String::from_utf8_lossy(slice))
 }
 
 pub struct ArtifactDifferenceWriter {
     codegen_records: Mutex<CodegenRecords>,
     codegen_filepath: PathBuf,
     verify_changes_against_filesystem: bool,
+    compiler_version: String,
 }
 
 impl ArtifactDifferenceWriter {
     pub fn new(
         codegen_filepath: PathBuf,
         verify_changes_against_filesystem: bool,
+        compiler_version: String,
     ) -> ArtifactDifferenceWriter {
         ArtifactDifferenceWriter {
             codegen_filepath,
             codegen_records: Mutex::new(CodegenRecords {
                 changed: Vec::new(),
+                patched: Vec::new(),
                 removed: Vec::new(),
             }),
             verify_changes_against_filesystem,
+            compiler_version,
         }
     }
 }
 
 impl ArtifactWriter for ArtifactDifferenceWriter {
     fn write_if_changed(&self, path: PathBuf, content: Vec<u8>) -> BuildProjectResult {
-        let should_include_artifact_in_codegen = !self.verify_changes_against_filesystem
-            || !content_is_same(&path, &content).map_err(|error| {
+        if self.verify_changes_against_filesystem {
+            let existing_content = read_if_exists(&path).map_err(|error| {
                 BuildProjectError::WriteFileError {
                     file: path.clone(),
                     source: error,
                 }
             })?;
-        if should_include_artifact_in_codegen {
-            self.codegen_records
-                .lock()
-                .unwrap()
-                .changed
-                .push(ArtifactUpdateRecord {
-                    path,
-                    data: content,
-                });
+            match existing_content {
+                Some(existing_content) if existing_content == content => {
+                    return Ok(());
+                }
+                Some(existing_content) => {
+                    match unified_diff(&existing_content, &content) {
+                        Some(unified_diff) => {
+                            self.codegen_records
+                                .lock()
+                                .unwrap()
+                                .patched
+                                .push(ArtifactPatchRecord { path, unified_diff });
+                        }
+                        None => {
+                            self.codegen_records.lock().unwrap().changed.push(
+                                ArtifactUpdateRecord {
+                                    path,
+                                    data: content,
+                                },
+                            );
+                        }
+                    }
+                    return Ok(());
+                }
+                None => {}
+            }
         }
+        self.codegen_records
+            .lock()
+            .unwrap()
+            .changed
+            .push(ArtifactUpdateRecord {
+                path,
+                data: content,
+            });
         Ok(())
     }
 
@@ -133,38 +367,288 @@ impl ArtifactWriter for ArtifactDifferenceWriter {
             source: error,
         })
     }
+
+    fn compiler_version(&self) -> &str {
+        &self.compiler_version
+    }
 }
 
-fn ensure_file_directory_exists(file_path: &PathBuf) -> io::Result<()> {
+fn ensure_file_directory_exists(fs: &dyn ArtifactFileSystem, file_path: &Path) -> io::Result<()> {
     if let Some(file_directory) = file_path.parent() {
-        if !file_directory.exists() {
-            create_dir_all(file_directory)?;
+        if !fs.exists(file_directory) {
+            fs.create_dir_all(file_directory)?;
         }
     }
 
     Ok(())
 }
 
-fn write_file(path: &PathBuf, content: &[u8]) -> io::Result<()> {
-    if path.exists() {
-        let existing_content = std::fs::read(path)?;
+fn write_file(fs: &dyn ArtifactFileSystem, path: &Path, content: &[u8]) -> io::Result<()> {
+    if fs.exists(path) {
+        let existing_content = fs.read(path)?;
         if existing_content == content {
             return Ok(());
         }
     } else {
-        ensure_file_directory_exists(path)?;
+        ensure_file_directory_exists(fs, path)?;
     }
 
-    let mut file = File::create(path)?;
-    file.write_all(&content)?;
-    Ok(())
+    fs.write(path, content)
 }
 
-fn content_is_same(path: &PathBuf, content: &Vec<u8>) -> io::Result<bool> {
+fn read_if_exists(path: &Path) -> io::Result<Option<Vec<u8>>> {
     if path.exists() {
-        let existing_content = std::fs::read(path)?;
-        Ok(&existing_content == content)
+        Ok(Some(std::fs::read(path)?))
     } else {
-        Ok(false)
+        Ok(None)
+    }
+}
+
+/// A single step of aligning `old`'s lines with `new`'s: a line common to
+/// both, a line only in `old`, or a line only in `new`.
+enum LineDiffOp {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// Above this many lines, the `O(n*m)` time and space of the LCS table in
+/// `diff_lines` turns into real CPU and memory pressure (generated
+/// GraphQL/codegen artifacts routinely reach tens of thousands of lines),
+/// so `unified_diff` bails out before building the table and lets the
+/// caller fall back to recording the full new content instead.
+const MAX_DIFFABLE_LINES: usize = 2000;
+
+/// Aligns the lines of `old` and `new` via a longest-common-subsequence of
+/// lines, the same primitive `diff -u` is built on.
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<LineDiffOp> {
+    let (n, m) = (old.len(), new.len());
+    let mut lcs_len = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs_len[i][j] = if old[i] == new[j] {
+                lcs_len[i + 1][j + 1] + 1
+            } else {
+                lcs_len[i + 1][j].max(lcs_len[i][j + 1])
+            };
+        }
+    }
+
+    let mut ops = Vec::with_capacity(n + m);
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if old[i] == new[j] {
+            ops.push(LineDiffOp::Equal);
+            i += 1;
+            j += 1;
+        } else if lcs_len[i + 1][j] >= lcs_len[i][j + 1] {
+            ops.push(LineDiffOp::Delete);
+            i += 1;
+        } else {
+            ops.push(LineDiffOp::Insert);
+            j += 1;
+        }
+    }
+    ops.extend(std::iter::repeat_with(|| LineDiffOp::Delete).take(n - i));
+    ops.extend(std::iter::repeat_with(|| LineDiffOp::Insert).take(m - j));
+    ops
+}
+
+/// Renders a minimal `diff -u`-style unified diff between `old` and `new`,
+/// with `CONTEXT` lines of surrounding context around each changed run.
+/// Returns an empty string when the two are identical, and `None` when
+/// either side exceeds `MAX_DIFFABLE_LINES` (the caller should fall back to
+/// full content).
+fn unified_diff(old: &[u8], new: &[u8]) -> Option<String> {
+    const CONTEXT: usize = 3;
+
+    let old_text = String::from_utf8_lossy(old);
+    let new_text = String::from_utf8_lossy(new);
+    let old_lines: Vec<&str> = old_text.lines().collect();
+    let new_lines: Vec<&str> = new_text.lines().collect();
+    if old_lines.len() > MAX_DIFFABLE_LINES || new_lines.len() > MAX_DIFFABLE_LINES {
+        return None;
+    }
+    let ops = diff_lines(&old_lines, &new_lines);
+
+    // `old_pos`/`new_pos` track, for each op, how many old/new lines were
+    // already consumed before it; a trailing sentinel captures the totals
+    // so a hunk's line count is a simple subtraction.
+    let mut old_pos = Vec::with_capacity(ops.len() + 1);
+    let mut new_pos = Vec::with_capacity(ops.len() + 1);
+    let (mut oi, mut ni) = (0, 0);
+    for op in &ops {
+        old_pos.push(oi);
+        new_pos.push(ni);
+        match op {
+            LineDiffOp::Equal => {
+                oi += 1;
+                ni += 1;
+            }
+            LineDiffOp::Delete => oi += 1,
+            LineDiffOp::Insert => ni += 1,
+        }
+    }
+    old_pos.push(oi);
+    new_pos.push(ni);
+
+    let changed_indices: Vec<usize> = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| !matches!(op, LineDiffOp::Equal))
+        .map(|(index, _)| index)
+        .collect();
+    if changed_indices.is_empty() {
+        return Some(String::new());
+    }
+
+    // Merge change positions into hunks padded by `CONTEXT` ops of
+    // surrounding, overlapping ranges.
+    let mut hunk_ranges: Vec<(usize, usize)> = Vec::new();
+    for index in changed_indices {
+        let start = index.saturating_sub(CONTEXT);
+        let end = (index + CONTEXT).min(ops.len() - 1);
+        match hunk_ranges.last_mut() {
+            Some((_, last_end)) if start <= *last_end + 1 => *last_end = (*last_end).max(end),
+            _ => hunk_ranges.push((start, end)),
+        }
+    }
+
+    let mut diff = String::new();
+    for (start, end) in hunk_ranges {
+        let old_start = old_pos[start];
+        let new_start = new_pos[start];
+        let old_len = old_pos[end + 1] - old_start;
+        let new_len = new_pos[end + 1] - new_start;
+        diff.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_start + 1,
+            old_len,
+            new_start + 1,
+            new_len
+        ));
+        for (op_index, op) in ops.iter().enumerate().take(end + 1).skip(start) {
+            match op {
+                LineDiffOp::Equal => {
+                    diff.push_str(&format!(" {}\n", old_lines[old_pos[op_index]]));
+                }
+                LineDiffOp::Delete => {
+                    diff.push_str(&format!("-{}\n", old_lines[old_pos[op_index]]));
+                }
+                LineDiffOp::Insert => {
+                    diff.push_str(&format!("+{}\n", new_lines[new_pos[op_index]]));
+                }
+            }
+        }
+    }
+    Some(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn writer(fs: Arc<dyn ArtifactFileSystem>) -> ArtifactFileWriter {
+        ArtifactFileWriter::with_fs(
+            PathBuf::from("hash-index.json"),
+            "test-compiler-v1".to_string(),
+            fs,
+        )
+    }
+
+    #[test]
+    fn in_memory_fs_tracks_mtime_per_path() {
+        let fs = InMemoryArtifactFileSystem::new();
+        let a = PathBuf::from("a.js");
+        let b = PathBuf::from("b.js");
+
+        fs.write(&a, b"a").unwrap();
+        let mtime_a = fs.modified_since_epoch_nanos(&a);
+
+        // Writing a different path (e.g. the hash index itself, during
+        // finalize()) must not change what `a` reports as its own
+        // modification time.
+        fs.write(&b, b"b").unwrap();
+        assert_eq!(fs.modified_since_epoch_nanos(&a), mtime_a);
+    }
+
+    #[test]
+    fn skips_rewrite_when_content_is_unchanged() {
+        let fs = Arc::new(InMemoryArtifactFileSystem::new());
+        let path = PathBuf::from("generated/foo.js");
+
+        let first = writer(Arc::clone(&fs) as Arc<dyn ArtifactFileSystem>);
+        first
+            .write_if_changed(path.clone(), b"content".to_vec())
+            .unwrap();
+        first.finalize().unwrap();
+        let written_once = fs.snapshot();
+
+        // A fresh writer that reloads the same persisted hash index should
+        // recognize the content as already up to date and skip the write
+        // entirely, rather than merely re-writing identical bytes.
+        let second = writer(Arc::clone(&fs) as Arc<dyn ArtifactFileSystem>);
+        second
+            .write_if_changed(path.clone(), b"content".to_vec())
+            .unwrap();
+
+        assert_eq!(fs.snapshot(), written_once);
+    }
+
+    #[test]
+    fn rewrites_when_content_changes() {
+        let fs = Arc::new(InMemoryArtifactFileSystem::new());
+        let path = PathBuf::from("generated/foo.js");
+
+        let first = writer(Arc::clone(&fs) as Arc<dyn ArtifactFileSystem>);
+        first
+            .write_if_changed(path.clone(), b"content".to_vec())
+            .unwrap();
+        first.finalize().unwrap();
+
+        let second = writer(Arc::clone(&fs) as Arc<dyn ArtifactFileSystem>);
+        second
+            .write_if_changed(path.clone(), b"new content".to_vec())
+            .unwrap();
+
+        assert_eq!(fs.read(&path).unwrap(), b"new content");
+    }
+
+    #[test]
+    fn hash_index_is_stale_after_a_compiler_version_change() {
+        let fs = InMemoryArtifactFileSystem::new();
+        let path = PathBuf::from("generated/foo.js");
+        fs.write(&path, b"content").unwrap();
+        let hash = hash_content(b"content");
+
+        let mut index = ArtifactHashIndex::default();
+        index.record(&fs, path.clone(), hash, 7, "v1".to_string());
+
+        assert!(index.is_up_to_date(&fs, &path, hash, 7, "v1"));
+        assert!(!index.is_up_to_date(&fs, &path, hash, 7, "v2"));
+    }
+
+    #[test]
+    fn unified_diff_of_identical_content_is_empty() {
+        let content = b"line one\nline two\nline three\n";
+        assert_eq!(unified_diff(content, content), Some(String::new()));
+    }
+
+    #[test]
+    fn unified_diff_reports_a_single_line_change() {
+        let old = b"one\ntwo\nthree\n";
+        let new = b"one\nTWO\nthree\n";
+        let diff = unified_diff(old, new).unwrap();
+        assert!(diff.contains("-two"));
+        assert!(diff.contains("+TWO"));
+        assert!(diff.contains(" one"));
+        assert!(diff.contains(" three"));
+    }
+
+    #[test]
+    fn unified_diff_falls_back_to_none_above_the_line_cap() {
+        let old = "line\n".repeat(MAX_DIFFABLE_LINES + 1);
+        let new = format!("{}extra\n", old);
+        assert_eq!(unified_diff(old.as_bytes(), new.as_bytes()), None);
     }
 }
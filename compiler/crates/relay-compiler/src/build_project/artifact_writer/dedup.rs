@@ -0,0 +1,261 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use super::{hash_content, BuildProjectError, BuildProjectResult};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{create_dir_all, File};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// An `ArtifactWriter` that stores the bytes of each distinct artifact
+/// exactly once, content-addressed by hash, and links every artifact path
+/// to its shared blob. Many generated artifacts across a large project are
+/// byte-identical (shared fragments, empty modules, boilerplate); this
+/// turns duplicate artifacts into a hardlink rather than another copy of
+/// the bytes on disk.
+pub struct ArtifactDedupWriter {
+    /// Directory the blob store lives under; blobs are written to
+    /// `<store_root>/blobs/<hash>`.
+    store_root: PathBuf,
+    refcounts_path: PathBuf,
+    state: Mutex<DedupState>,
+    compiler_version: String,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct DedupState {
+    /// Number of artifact paths currently hardlinked to each blob hash.
+    blob_refcounts: HashMap<String, u64>,
+    /// The blob hash (and compiler version that produced it) each artifact
+    /// path is currently linked to, so a rewrite or removal can find (and
+    /// decrement) the right blob. Tracking `compiler_version` here (rather
+    /// than stamping it into the blob's own bytes) is what makes a compiler
+    /// upgrade force every artifact to be treated as stale.
+    artifacts: HashMap<PathBuf, DedupArtifactEntry>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct DedupArtifactEntry {
+    hash: String,
+    compiler_version: String,
+}
+
+impl ArtifactDedupWriter {
+    pub fn new(store_root: PathBuf, compiler_version: String) -> Self {
+        let refcounts_path = store_root.join("refcounts.json");
+        ArtifactDedupWriter {
+            state: Mutex::new(DedupState::load(&refcounts_path)),
+            store_root,
+            refcounts_path,
+            compiler_version,
+        }
+    }
+
+    fn blob_path(&self, hash: &str) -> PathBuf {
+        self.store_root.join("blobs").join(hash)
+    }
+}
+
+impl super::ArtifactWriter for ArtifactDedupWriter {
+    fn write_if_changed(&self, path: PathBuf, content: Vec<u8>) -> BuildProjectResult {
+        let hash = format!("{:016x}", hash_content(&content));
+
+        let mut state = self.state.lock().unwrap();
+        // A matching index entry alone isn't enough: the output directory
+        // may have been wiped (e.g. `rm -rf generated/`) since the index
+        // was last persisted, in which case the hardlink needs recreating
+        // even though the content and compiler version haven't changed.
+        let up_to_date = matches!(
+            state.artifacts.get(&path),
+            Some(entry) if entry.hash == hash && entry.compiler_version == self.compiler_version
+        ) && path.exists();
+        if up_to_date {
+            return Ok(());
+        }
+
+        let blob_path = self.blob_path(&hash);
+        if !blob_path.exists() {
+            write_blob(&blob_path, &content).map_err(|error| BuildProjectError::WriteFileError {
+                file: blob_path.clone(),
+                source: error,
+            })?;
+        }
+
+        relink_artifact(&path, &blob_path).map_err(|error| BuildProjectError::WriteFileError {
+            file: path.clone(),
+            source: error,
+        })?;
+
+        let previous = state.artifacts.insert(
+            path,
+            DedupArtifactEntry {
+                hash: hash.clone(),
+                compiler_version: self.compiler_version.clone(),
+            },
+        );
+        // Only the blob reference actually changing should move a
+        // refcount: re-affirming the same path/hash pair (e.g. because
+        // only the compiler version or the on-disk link needed refreshing)
+        // must not double-count a reference that's already accounted for.
+        match previous {
+            Some(previous) if previous.hash == hash => {}
+            Some(previous) => {
+                decrement_refcount(&mut state.blob_refcounts, &previous.hash);
+                *state.blob_refcounts.entry(hash).or_insert(0) += 1;
+            }
+            None => {
+                *state.blob_refcounts.entry(hash).or_insert(0) += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&self, path: PathBuf) -> BuildProjectResult {
+        std::fs::remove_file(&path).unwrap_or_else(|_| {
+            info!("tried to delete already deleted file: {:?}", path);
+        });
+        let mut state = self.state.lock().unwrap();
+        if let Some(entry) = state.artifacts.remove(&path) {
+            decrement_refcount(&mut state.blob_refcounts, &entry.hash);
+        }
+        Ok(())
+    }
+
+    fn finalize(&self) -> crate::errors::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let dead_blobs: Vec<String> = state
+            .blob_refcounts
+            .iter()
+            .filter(|(_, &refcount)| refcount == 0)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        for hash in dead_blobs {
+            let _ = std::fs::remove_file(self.blob_path(&hash));
+            state.blob_refcounts.remove(&hash);
+        }
+
+        state
+            .save(&self.refcounts_path)
+            .map_err(|error| crate::errors::Error::WriteFileError {
+                file: self.refcounts_path.clone(),
+                source: error,
+            })
+    }
+
+    fn compiler_version(&self) -> &str {
+        &self.compiler_version
+    }
+}
+
+impl DedupState {
+    fn load(path: &Path) -> DedupState {
+        std::fs::read(path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(directory) = path.parent() {
+            create_dir_all(directory)?;
+        }
+        let json = serde_json::to_vec(self)
+            .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+        write_blob(path, &json)
+    }
+}
+
+fn decrement_refcount(refcounts: &mut HashMap<String, u64>, hash: &str) {
+    if let Some(refcount) = refcounts.get_mut(hash) {
+        *refcount = refcount.saturating_sub(1);
+    }
+}
+
+/// Replaces whatever is at `path` (file, stale hardlink, or nothing) with a
+/// fresh hardlink to `blob_path`.
+fn relink_artifact(path: &Path, blob_path: &Path) -> io::Result<()> {
+    if let Some(directory) = path.parent() {
+        if !directory.exists() {
+            create_dir_all(directory)?;
+        }
+    }
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    std::fs::hard_link(blob_path, path)
+}
+
+/// Writes `content` to `path` atomically (temp file in the same directory,
+/// flushed and fsynced, then renamed into place), matching the discipline
+/// artifacts are written with elsewhere in this module.
+fn write_blob(path: &Path, content: &[u8]) -> io::Result<()> {
+    if let Some(directory) = path.parent() {
+        if !directory.exists() {
+            create_dir_all(directory)?;
+        }
+    }
+    let temp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+    let result = (|| -> io::Result<()> {
+        let mut temp_file = File::create(&temp_path)?;
+        temp_file.write_all(content)?;
+        temp_file.flush()?;
+        temp_file.sync_all()?;
+        std::fs::rename(&temp_path, path)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::ArtifactWriter;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static TEST_DIR_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    fn temp_store_root() -> PathBuf {
+        let id = TEST_DIR_COUNTER.fetch_add(1, Ordering::SeqCst);
+        std::env::temp_dir().join(format!("relay-artifact-dedup-test-{}-{}", std::process::id(), id))
+    }
+
+    #[test]
+    fn rewriting_unchanged_content_does_not_inflate_the_refcount() {
+        let store_root = temp_store_root();
+        let artifact_path = store_root.join("artifact.js");
+        let writer = ArtifactDedupWriter::new(store_root.clone(), "v1".to_string());
+
+        writer
+            .write_if_changed(artifact_path.clone(), b"content".to_vec())
+            .unwrap();
+
+        // Simulate `rm -rf generated/`: the blob store survives, but the
+        // artifact path itself is gone, so the fast path can't be taken.
+        std::fs::remove_file(&artifact_path).unwrap();
+
+        writer
+            .write_if_changed(artifact_path.clone(), b"content".to_vec())
+            .unwrap();
+        writer
+            .write_if_changed(artifact_path.clone(), b"content".to_vec())
+            .unwrap();
+
+        let hash = format!("{:016x}", hash_content(b"content"));
+        assert_eq!(
+            writer.state.lock().unwrap().blob_refcounts.get(&hash),
+            Some(&1)
+        );
+
+        let _ = std::fs::remove_dir_all(&store_root);
+    }
+}
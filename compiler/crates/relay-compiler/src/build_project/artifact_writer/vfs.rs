@@ -0,0 +1,171 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use std::collections::HashMap;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+/// The filesystem operations `ArtifactWriter`s need, factored out behind a
+/// trait so the concrete backend can be swapped: [`OsArtifactFileSystem`]
+/// for real builds, [`InMemoryArtifactFileSystem`] for hermetic tests, or a
+/// caller-supplied implementation that routes writes to a remote/object
+/// store or captures them for inspection.
+pub trait ArtifactFileSystem: Send + Sync {
+    fn exists(&self, path: &Path) -> bool;
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Writes `content` to `path` as a single atomic operation: readers
+    /// only ever observe the previous complete content or the new complete
+    /// content, never a partial write.
+    fn write(&self, path: &Path, content: &[u8]) -> io::Result<()>;
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    /// Nanoseconds since the Unix epoch that `path` was last modified, or
+    /// `None` if that can't be determined (e.g. the path doesn't exist).
+    /// Sub-second resolution matters here: whole-second resolution can't
+    /// tell an artifact apart from a hand-edit made within the same second.
+    fn modified_since_epoch_nanos(&self, path: &Path) -> Option<u128>;
+}
+
+/// The default backend: reads and writes the real filesystem via
+/// `std::fs`.
+#[derive(Default)]
+pub struct OsArtifactFileSystem;
+
+impl ArtifactFileSystem for OsArtifactFileSystem {
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn write(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        let directory = path.parent().unwrap_or_else(|| Path::new("."));
+        let temp_path = directory.join(format!(
+            ".{}.{}.tmp",
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or("artifact"),
+            std::process::id()
+        ));
+
+        let result = (|| -> io::Result<()> {
+            let mut temp_file = std::fs::File::create(&temp_path)?;
+            temp_file.write_all(content)?;
+            temp_file.flush()?;
+            temp_file.sync_all()?;
+            std::fs::rename(&temp_path, path)
+        })();
+
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+
+        result
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn modified_since_epoch_nanos(&self, path: &Path) -> Option<u128> {
+        std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_nanos())
+    }
+}
+
+/// An in-memory backend for fast, hermetic tests of the build pipeline:
+/// nothing touches disk, and every write is visible to the test via
+/// [`InMemoryArtifactFileSystem::snapshot`].
+#[derive(Default)]
+pub struct InMemoryArtifactFileSystem {
+    files: Mutex<HashMap<PathBuf, Vec<u8>>>,
+    // There's no real mtime for an in-memory file, so every write hands out
+    // the next tick of a monotonically increasing counter and records it
+    // as that path's modification time in nanoseconds — per path, like a
+    // real filesystem, so writing one file doesn't change what every other
+    // path reports. That's all the hash index needs to notice an
+    // out-of-band change, including two writes within the same wall-clock
+    // second.
+    mtimes_nanos: Mutex<HashMap<PathBuf, u64>>,
+    next_mtime_nanos: AtomicU64,
+}
+
+impl InMemoryArtifactFileSystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the current contents of every file that has been written.
+    pub fn snapshot(&self) -> HashMap<PathBuf, Vec<u8>> {
+        self.files.lock().unwrap().clone()
+    }
+}
+
+impl ArtifactFileSystem for InMemoryArtifactFileSystem {
+    fn exists(&self, path: &Path) -> bool {
+        self.files.lock().unwrap().contains_key(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.files
+            .lock()
+            .unwrap()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn create_dir_all(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), content.to_vec());
+        let mtime_nanos = self.next_mtime_nanos.fetch_add(1, Ordering::SeqCst);
+        self.mtimes_nanos
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), mtime_nanos);
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.mtimes_nanos.lock().unwrap().remove(path);
+        self.files
+            .lock()
+            .unwrap()
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn modified_since_epoch_nanos(&self, path: &Path) -> Option<u128> {
+        self.mtimes_nanos
+            .lock()
+            .unwrap()
+            .get(path)
+            .map(|&mtime_nanos| mtime_nanos as u128)
+    }
+}